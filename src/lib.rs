@@ -1,4 +1,4 @@
-#![feature(unsafe_destructor, alloc)]
+#![feature(unsafe_destructor, alloc, default_type_params, raw)]
 #![cfg_attr(test, feature(std_misc))]
 
 //! bip (`Box` in place) provides a fully generic in-place `map` for
@@ -8,34 +8,159 @@
 //! crates.io](http://crates.io/crates/bip). [Source](https://github.com/huonw/bip).
 
 use std::rt::heap;
-use std::{mem,ptr};
+use std::{mem,ptr,raw};
+use std::ops::{Deref, DerefMut};
 
-// avoid memory leaks by freeing the memory, without
-// running the destructor of the contents
-struct Dropper<T> {
-    ptr: *mut T
+/// A source of raw, untyped memory.
+///
+/// This mirrors the `Alloc` trait being prototyped for a constified
+/// `Box<T, A>` in upstream `alloc`, so that `bip` isn't tied to the
+/// global heap: an arena, a pool, or a kernel-style allocator can all
+/// implement it.
+///
+/// # Safety
+///
+/// An implementation must behave like `std::rt::heap`: `allocate` and
+/// `reallocate` return either a suitably aligned block of at least
+/// `size` bytes or a null pointer on failure, and `deallocate` must
+/// accept exactly the `(ptr, size, align)` that produced it.
+pub unsafe trait Allocator {
+    unsafe fn allocate(&self, size: usize, align: usize) -> *mut u8;
+    unsafe fn deallocate(&self, ptr: *mut u8, size: usize, align: usize);
+    unsafe fn reallocate(&self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> *mut u8;
+}
+
+/// The global heap, via `std::rt::heap`. This is the allocator used by
+/// `map_in_place`, and the default for `AllocBox`.
+#[derive(Clone, Copy, Default)]
+pub struct Heap;
+
+unsafe impl Allocator for Heap {
+    unsafe fn allocate(&self, size: usize, align: usize) -> *mut u8 {
+        heap::allocate(size, align)
+    }
+    unsafe fn deallocate(&self, ptr: *mut u8, size: usize, align: usize) {
+        heap::deallocate(ptr, size, align)
+    }
+    unsafe fn reallocate(&self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> *mut u8 {
+        heap::reallocate(ptr, old_size, size, align)
+    }
+}
+
+// avoid memory leaks by freeing the memory, without running the
+// destructor of the contents. Borrows the allocator rather than owning
+// it, so that ownership of a long-lived `A` can stay with the caller
+// (e.g. `AllocBox`) across the call.
+struct Dropper<'a, T, A: Allocator + 'a> {
+    ptr: *mut T,
+    alloc: &'a A,
 }
 
 #[unsafe_destructor]
-impl<T> Drop for Dropper<T> {
+impl<'a, T, A: Allocator> Drop for Dropper<'a, T, A> {
     fn drop(&mut self) {
         unsafe {
-            heap::deallocate(self.ptr as *mut u8, mem::size_of::<T>(), mem::align_of::<T>());
+            self.alloc.deallocate(self.ptr as *mut u8, mem::size_of::<T>(), mem::align_of::<T>());
         }
     }
 }
 
+// shared by `map_in_place`, `map_in_place_in`, `try_map_in_place` and
+// the `rc`/`arc` variants: given that the `T` at `ptr`
+// has already been read out (so that slot is logically
+// uninitialized) and `new` has been produced from it, pick a buffer
+// for `U` -- reusing `ptr`'s allocation, resizing it, or falling back
+// to a fresh block -- and write `new` into it. Returns a pointer to
+// the `U` with the same provenance as the (possibly new) allocation.
+unsafe fn raw_finish_map<T, U, A>(ptr: *mut T, alloc: &A, new: U) -> *mut U where A: Allocator {
+    let old_size = mem::size_of::<T>();
+    let old_align = mem::align_of::<T>();
+    let new_size = mem::size_of::<U>();
+    let new_align = mem::align_of::<U>();
+
+    // reuse the allocation as-is: same size, and `U`'s alignment
+    // requirement is no stronger than `T`'s.
+    if old_size == new_size && old_align >= new_align {
+        ptr::write(ptr as *mut U, new);
+        return ptr as *mut U;
+    }
+
+    // no existing allocation to grow or shrink: a zero-sized type
+    // never points at a real allocation. Free `T`'s block first, since
+    // nothing else will.
+    if new_size == 0 {
+        if old_size != 0 {
+            alloc.deallocate(ptr as *mut u8, old_size, old_align);
+        }
+        ptr::write(heap::EMPTY as *mut U, new);
+        return heap::EMPTY as *mut U;
+    }
+
+    // `reallocate` is only contractually allowed to grow or shrink a
+    // block in place, not to strengthen its alignment, so an alignment
+    // increase needs a fresh, properly-aligned block, just like
+    // growing from a zero-sized `T` does.
+    if old_size == 0 || new_align > old_align {
+        return raw_fresh_alloc(ptr, old_size, old_align, alloc, new);
+    }
+
+    let new_ptr = alloc.reallocate(ptr as *mut u8, old_size, new_size, new_align);
+    if !new_ptr.is_null() {
+        ptr::write(new_ptr as *mut U, new);
+        return new_ptr as *mut U;
+    }
+
+    // the in-place realloc failed: fall back to a fresh block, freeing
+    // the old one ourselves (the caller's `Dropper` only knows about
+    // `T`'s layout).
+    raw_fresh_alloc(ptr, old_size, old_align, alloc, new)
+}
+
+// shared tail of `raw_finish_map`: allocate a fresh block sized for
+// `U`, move `new` into it, and free the old `T` block if it had one.
+unsafe fn raw_fresh_alloc<T, U, A>(ptr: *mut T, old_size: usize, old_align: usize, alloc: &A, new: U) -> *mut U
+    where A: Allocator
+{
+    let new_size = mem::size_of::<U>();
+    let new_align = mem::align_of::<U>();
+
+    let fresh = alloc.allocate(new_size, new_align);
+    if fresh.is_null() {
+        heap::oom()
+    }
+    ptr::write(fresh as *mut U, new);
+    if old_size != 0 {
+        alloc.deallocate(ptr as *mut u8, old_size, old_align);
+    }
+    fresh as *mut U
+}
+
+// shared by `map_in_place` and `map_in_place_in`: run `f` over the
+// value at `ptr` and hand the result to `raw_finish_map`.
+unsafe fn raw_map_in_place<T, U, F, A>(ptr: *mut T, alloc: &A, f: F) -> *mut U
+    where F: FnOnce(T) -> U, A: Allocator
+{
+    let dropper = Dropper { ptr: ptr, alloc: alloc };
+    let old = ptr::read(dropper.ptr);
+    let new = f(old);
+    let result = raw_finish_map(dropper.ptr, alloc, new);
+    mem::forget(dropper);
+    result
+}
+
 /// Execute `f` on the data in `x`, replacing the output into the same
-/// allocation.
-///
-/// This is semantically equivalent to `Box::new(f(*x))`, but avoids
-/// the allocation by reusing the memory of `x` directly. `map` will
-/// not cause unsafety or leak memory if `f` panics.
+/// allocation where possible.
 ///
-/// `T` and `U` must have the same size, and the alignment (measured
-/// by `std::mem::min_align_of`) of `T` must be at least as large as
-/// that of `U`. A violation of either of these requirements will
-/// result in a runtime panic.
+/// This is semantically equivalent to `Box::new(f(*x))`. When `T` and
+/// `U` have the same size and `U`'s alignment (measured by
+/// `std::mem::align_of`) is no stronger than `T`'s, the existing
+/// allocation is reused directly. Otherwise, if `U`'s alignment is no
+/// stronger than `T`'s, the allocation is resized with
+/// `heap::reallocate`, falling back to a fresh allocation if that
+/// fails; a stronger alignment, or a zero-sized `T`, gets a fresh
+/// allocation directly, and a zero-sized `U` needs no allocation at
+/// all. Either way, `map_in_place` will not cause
+/// unsafety or leak memory if `f` panics.
 ///
 /// # Example
 ///
@@ -49,29 +174,507 @@ impl<T> Drop for Dropper<T> {
 /// assert_eq!(address, &*new_x as *const _ as usize);
 /// ```
 pub fn map_in_place<T, U, F>(x: Box<T>, f: F) -> Box<U> where F: FnOnce(T) -> U {
-    assert!(mem::size_of::<T>() == mem::size_of::<U>(),
-            "map_in_place: `T` and `U` are of different sizes");
-    assert!(mem::align_of::<T>() >= mem::align_of::<U>(),
-            "map_in_place: alignment of `U` is too large");
+    unsafe {
+        let ptr = raw_map_in_place(mem::transmute(x), &Heap, f);
+        mem::transmute(ptr)
+    }
+}
 
+/// A fallible sibling of `map_in_place`: on success, behaves exactly
+/// like `map_in_place`; on failure, `x` is handed back untouched
+/// instead of being lost.
+///
+/// Because `f` takes `T` by value, it is the one place that can
+/// recover it once a transform turns out not to work out, so on
+/// failure `f` must hand `T` back alongside its error -- mirroring
+/// `mpsc::Sender::send`'s `SendError<T>` -- and `try_map_in_place`
+/// writes it back into the same allocation and reconstructs `x`. This
+/// gives callers a non-destructive transform they can retry.
+///
+/// # Example
+///
+/// ```rust
+/// let x = Box::new(-1_i32);
+/// let err = bip::try_map_in_place(x, |x| {
+///     if x >= 0 { Ok(x as u32) } else { Err((x, "negative")) }
+/// }).unwrap_err();
+/// assert_eq!(*err.0, -1);
+/// assert_eq!(err.1, "negative");
+/// ```
+pub fn try_map_in_place<T, U, E, F>(x: Box<T>, f: F) -> Result<Box<U>, (Box<T>, E)>
+    where F: FnOnce(T) -> Result<U, (T, E)>
+{
     unsafe {
-        let dropper = Dropper {
-            ptr: mem::transmute(x)
-        };
+        let heap = Heap;
+        let ptr: *mut T = mem::transmute(x);
+        let dropper = Dropper { ptr: ptr, alloc: &heap };
         let old = ptr::read(dropper.ptr);
-        let new = f(old);
-        ptr::write(dropper.ptr as *mut U, new);
 
-        let ret: Box<U> = mem::transmute(dropper.ptr);
+        match f(old) {
+            Ok(new) => {
+                let new_ptr = raw_finish_map(dropper.ptr, &heap, new);
+                mem::forget(dropper);
+                Ok(mem::transmute(new_ptr))
+            }
+            Err((old, e)) => {
+                ptr::write(dropper.ptr, old);
+                let ret: Box<T> = mem::transmute(dropper.ptr);
+                mem::forget(dropper);
+                Err((ret, e))
+            }
+        }
+    }
+}
+
+/// A possibly-uninitialized `T`, as handed to the callback of
+/// `map_in_place_uninit`.
+///
+/// This is `bip`'s own minimal stand-in for the `MaybeUninit`/
+/// "new_uninit" box APIs being prototyped upstream; it exposes just
+/// enough of the slot it wraps to initialize it, either all at once or
+/// incrementally through a raw pointer.
+pub struct MaybeUninit<T> {
+    ptr: *mut T,
+}
+
+impl<T> MaybeUninit<T> {
+    /// Fully initialize the slot with `value`.
+    pub fn set(&mut self, value: T) {
+        unsafe { ptr::write(self.ptr, value) }
+    }
+
+    /// A raw pointer to the (possibly uninitialized) slot, for
+    /// initializing `T` incrementally (e.g. field by field) instead of
+    /// constructing it on the stack and moving it in with `set`.
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr
+    }
+}
+
+// frees a raw block on panic without attempting to drop whatever
+// (possibly partially-initialized) value might be in it; used once
+// `map_in_place_uninit` has settled on `U`'s own allocation and
+// `Dropper`'s `T`-shaped view of the memory no longer applies.
+struct UninitDropper {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+}
+
+#[unsafe_destructor]
+impl Drop for UninitDropper {
+    fn drop(&mut self) {
+        unsafe {
+            heap::deallocate(self.ptr, self.size, self.align);
+        }
+    }
+}
+
+/// A staged, lower-level sibling of `map_in_place`: instead of handing
+/// back a `U` to write in, `f` is given `T` together with a
+/// `&mut MaybeUninit<U>` aliasing the (possibly resized) allocation,
+/// and is responsible for initializing it itself.
+///
+/// This is useful for a large `U` that would be wasteful to build on
+/// the stack and move in -- `f` can construct it field-by-field
+/// straight into its final location. Layout is handled exactly like
+/// `map_in_place`: `x`'s allocation is reused if the sizes and
+/// alignments allow, otherwise it is grown or shrunk, or replaced with
+/// a fresh block when `U` needs a stronger alignment, `T` is
+/// zero-sized, or resizing fails. If `f` panics before finishing, the
+/// block is freed without running any destructor on the
+/// partially-written `U` -- there is no valid `U` yet to drop.
+///
+/// # Safety
+///
+/// `f` must fully initialize the slot (via `MaybeUninit::set` or by
+/// writing through `MaybeUninit::as_mut_ptr`) before returning. This
+/// is the same contract as `std`'s `MaybeUninit::assume_init`: the
+/// return value is produced by reinterpreting the slot as a `U`
+/// whether or not `f` actually wrote one, so a no-op or early-returning
+/// `f` hands back a `Box` over uninitialized memory.
+pub unsafe fn map_in_place_uninit<T, U, F>(x: Box<T>, f: F) -> Box<U>
+    where F: FnOnce(T, &mut MaybeUninit<U>)
+{
+    let old_size = mem::size_of::<T>();
+    let old_align = mem::align_of::<T>();
+    let new_size = mem::size_of::<U>();
+    let new_align = mem::align_of::<U>();
+
+    let heap = Heap;
+    let dropper = Dropper { ptr: mem::transmute(x), alloc: &heap };
+    let old = ptr::read(dropper.ptr);
+
+    // reuse the allocation as-is: `Dropper` still owns freeing it
+    // under `T`'s layout if `f` panics.
+    if old_size == new_size && old_align >= new_align {
+        let dst = dropper.ptr as *mut U;
+        let mut slot = MaybeUninit { ptr: dst };
+        f(old, &mut slot);
+        mem::forget(dropper);
+        return mem::transmute(dst);
+    }
+
+    // no existing allocation to grow or shrink: free `T`'s block
+    // first, since nothing else will.
+    if new_size == 0 {
+        if old_size != 0 {
+            heap.deallocate(dropper.ptr as *mut u8, old_size, old_align);
+        }
+        mem::forget(dropper);
+        let dst = heap::EMPTY as *mut U;
+        let mut slot = MaybeUninit { ptr: dst };
+        f(old, &mut slot);
+        return mem::transmute(dst);
+    }
 
+    // `reallocate` is only contractually allowed to grow or shrink
+    // a block in place, not to strengthen its alignment, so an
+    // alignment increase needs a fresh, properly-aligned block,
+    // just like growing from a zero-sized `T` does -- and unlike
+    // an in-place grow/shrink, the old block is left untouched and
+    // must be freed by hand.
+    let dst = if old_size == 0 || new_align > old_align {
+        let fresh = heap.allocate(new_size, new_align);
+        if fresh.is_null() {
+            heap::oom()
+        }
+        if old_size != 0 {
+            heap.deallocate(dropper.ptr as *mut u8, old_size, old_align);
+        }
         mem::forget(dropper);
-        ret
+        fresh as *mut U
+    } else {
+        let new_ptr = heap.reallocate(dropper.ptr as *mut u8, old_size, new_size, new_align);
+        if !new_ptr.is_null() {
+            // the old block was grown/shrunk in place; `Dropper`
+            // must not fire.
+            mem::forget(dropper);
+            new_ptr as *mut U
+        } else {
+            let fresh = heap.allocate(new_size, new_align);
+            if fresh.is_null() {
+                heap::oom()
+            }
+            heap.deallocate(dropper.ptr as *mut u8, old_size, old_align);
+            mem::forget(dropper);
+            fresh as *mut U
+        }
+    };
+
+    // from here, `dst` is its own block under `U`'s layout, with
+    // nothing of `T`'s left to free.
+    let guard = UninitDropper { ptr: dst as *mut u8, size: new_size, align: new_align };
+    let mut slot = MaybeUninit { ptr: dst };
+    f(old, &mut slot);
+    mem::forget(guard);
+
+    mem::transmute(dst)
+}
+
+/// A box whose backing memory came from `alloc` rather than
+/// necessarily the global heap.
+///
+/// This plays the role of a constified `Box<T, A>`: the standard
+/// `Box<T>` is hard-wired to the global heap, so `bip` needs its own
+/// allocator-aware box to let `map_in_place_in` reuse allocations from
+/// arenas, pools, or kernel-style allocators.
+pub struct AllocBox<T, A: Allocator = Heap> {
+    ptr: *mut T,
+    alloc: A,
+}
+
+impl<T, A: Allocator> AllocBox<T, A> {
+    /// Move `value` into a fresh allocation from `alloc`.
+    pub fn new_in(value: T, alloc: A) -> AllocBox<T, A> {
+        unsafe {
+            let size = mem::size_of::<T>();
+            let ptr = if size == 0 {
+                heap::EMPTY as *mut T
+            } else {
+                let ptr = alloc.allocate(size, mem::align_of::<T>());
+                if ptr.is_null() {
+                    heap::oom()
+                }
+                ptr as *mut T
+            };
+            ptr::write(ptr, value);
+            AllocBox { ptr: ptr, alloc: alloc }
+        }
+    }
+}
+
+impl<T, A: Allocator> Deref for AllocBox<T, A> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T, A: Allocator> DerefMut for AllocBox<T, A> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+#[unsafe_destructor]
+impl<T, A: Allocator> Drop for AllocBox<T, A> {
+    fn drop(&mut self) {
+        unsafe {
+            ptr::read(self.ptr);
+            if mem::size_of::<T>() != 0 {
+                self.alloc.deallocate(self.ptr as *mut u8, mem::size_of::<T>(), mem::align_of::<T>());
+            }
+        }
+    }
+}
+
+/// The `AllocBox` equivalent of `map_in_place`: execute `f` on the
+/// data in `x`, reusing its allocation where possible, and keeping the
+/// same allocator for the result.
+///
+/// This is semantically equivalent to `AllocBox::new_in(f(*x), alloc)`
+/// for `x`'s allocator, but avoids the allocation when it can.
+pub fn map_in_place_in<T, U, F, A>(x: AllocBox<T, A>, f: F) -> AllocBox<U, A>
+    where F: FnOnce(T) -> U, A: Allocator
+{
+    unsafe {
+        // take `x` apart without running its `Drop` impl, so the
+        // allocator can be reused for the result below.
+        let ptr = x.ptr;
+        let alloc = ptr::read(&x.alloc);
+        mem::forget(x);
+
+        let new_ptr = raw_map_in_place(ptr, &alloc, f);
+        AllocBox { ptr: new_ptr, alloc: alloc }
+    }
+}
+
+// on panic of `f` at `produced`, the `T` at that index has already
+// been moved into `f` and is gone; the `U`s at `[0, produced)` and the
+// `T`s at `(produced, len)` are the only live values left to drop.
+struct SliceMapGuard<T, U> {
+    src: *mut T,
+    dst: *mut U,
+    len: usize,
+    produced: usize,
+}
+
+#[unsafe_destructor]
+impl<T, U> Drop for SliceMapGuard<T, U> {
+    fn drop(&mut self) {
+        unsafe {
+            for i in 0..self.produced {
+                ptr::read(self.dst.offset(i as isize));
+            }
+            for i in (self.produced + 1)..self.len {
+                ptr::read(self.src.offset(i as isize));
+            }
+
+            let old_total = mem::size_of::<T>() * self.len;
+            let new_total = mem::size_of::<U>() * self.len;
+
+            if self.src as *mut u8 == self.dst as *mut u8 {
+                // a single buffer backs both views.
+                if old_total != 0 {
+                    heap::deallocate(self.src as *mut u8, old_total, mem::align_of::<T>());
+                }
+            } else {
+                if old_total != 0 {
+                    heap::deallocate(self.src as *mut u8, old_total, mem::align_of::<T>());
+                }
+                if new_total != 0 {
+                    heap::deallocate(self.dst as *mut u8, new_total, mem::align_of::<U>());
+                }
+            }
+        }
+    }
+}
+
+/// The element-wise counterpart to `map_in_place`: apply `f` to every
+/// element of `x`, reusing the single backing buffer when `T` and `U`
+/// have the same size and `U`'s alignment is no stronger than `T`'s.
+///
+/// This is semantically equivalent to
+/// `x.into_vec().into_iter().map(f).collect::<Vec<_>>().into_boxed_slice()`,
+/// but avoids a second allocation when the layouts line up. If `f`
+/// panics partway through, every element produced so far and every
+/// element not yet consumed is dropped, and the buffer(s) are freed;
+/// nothing is leaked or double-dropped.
+pub fn map_in_place_slice<T, U, F>(x: Box<[T]>, mut f: F) -> Box<[U]> where F: FnMut(T) -> U {
+    let len = x.len();
+    let old_size = mem::size_of::<T>();
+    let old_align = mem::align_of::<T>();
+    let new_size = mem::size_of::<U>();
+    let new_align = mem::align_of::<U>();
+    let same_layout = old_size == new_size && old_align >= new_align;
+
+    unsafe {
+        let src: *mut T = mem::transmute::<_, raw::Slice<T>>(x).data as *mut T;
+
+        let dst: *mut U = if same_layout {
+            src as *mut U
+        } else {
+            let total_new = new_size * len;
+            if total_new == 0 {
+                heap::EMPTY as *mut U
+            } else {
+                let p = heap::allocate(total_new, new_align);
+                if p.is_null() {
+                    heap::oom()
+                }
+                p as *mut U
+            }
+        };
+
+        let mut guard = SliceMapGuard { src: src, dst: dst, len: len, produced: 0 };
+
+        while guard.produced < len {
+            let i = guard.produced;
+            let value = ptr::read(guard.src.offset(i as isize));
+            let new_value = f(value);
+            ptr::write(guard.dst.offset(i as isize), new_value);
+            guard.produced += 1;
+        }
+
+        // success: if the source and destination are distinct
+        // buffers, the old one has now been fully drained and must be
+        // freed ourselves (the guard no longer knows to do it).
+        if src as *mut u8 != dst as *mut u8 {
+            let total_old = old_size * len;
+            if total_old != 0 {
+                heap::deallocate(src as *mut u8, total_old, old_align);
+            }
+        }
+        mem::forget(guard);
+
+        mem::transmute(raw::Slice { data: dst as *const U, len: len })
+    }
+}
+
+/// `map_in_place` for `Rc<T>`.
+pub mod rc {
+    use std::rc::Rc;
+    use std::cell::Cell;
+    use std::{mem, ptr};
+    use super::{Dropper, Heap, raw_finish_map};
+
+    // Mirrors the private layout of `std::rc::RcBox<T>` (`strong`
+    // count, `weak` count, then the value), which has been stable
+    // since `Rc` was introduced but is not exposed by any public API.
+    // There's no sanctioned way to reuse `Rc`'s allocation from
+    // outside `std`, so `try_map_in_place` relies on this the same way
+    // the rest of this crate relies on `Box`'s own raw representation.
+    struct RcBoxLayout<T> {
+        strong: Cell<usize>,
+        weak: Cell<usize>,
+        value: T,
+    }
+
+    /// Execute `f` on the value pointed at by `x`, producing an
+    /// `Rc<U>` that reuses `x`'s allocation, or hand `x` and `f` back
+    /// unchanged if `x` isn't uniquely owned.
+    ///
+    /// Uniqueness is exactly the condition `Rc::get_mut` checks (no
+    /// other `Rc` and no live `Weak`); on that path the backing
+    /// `RcBox<T>` is grown, shrunk, or replaced with a fresh block
+    /// precisely like `bip::map_in_place` does for a `Box`, with the
+    /// strong/weak counters carried over untouched.
+    pub fn try_map_in_place<T, U, F>(mut x: Rc<T>, f: F) -> Result<Rc<U>, (Rc<T>, F)>
+        where F: FnOnce(T) -> U
+    {
+        if Rc::get_mut(&mut x).is_none() {
+            return Err((x, f));
+        }
+
+        unsafe {
+            let heap = Heap;
+            let raw: *mut RcBoxLayout<T> = mem::transmute(x);
+            let dropper = Dropper { ptr: raw, alloc: &heap };
+            let old = ptr::read(dropper.ptr);
+            let new = RcBoxLayout { strong: old.strong, weak: old.weak, value: f(old.value) };
+            let new_raw = raw_finish_map(dropper.ptr, &heap, new);
+            mem::forget(dropper);
+            Ok(mem::transmute(new_raw))
+        }
+    }
+
+    /// Execute `f` on the value pointed at by `x`, producing an
+    /// `Rc<U>`.
+    ///
+    /// When `x` is uniquely owned, this reuses its allocation via
+    /// `try_map_in_place`. Otherwise the allocation is shared, so the
+    /// value is cloned first and `x` is left untouched for the other
+    /// holders -- callers that know `x` is unique and don't want to
+    /// carry a `T: Clone` bound can use `try_map_in_place` directly.
+    pub fn map_in_place<T, U, F>(x: Rc<T>, f: F) -> Rc<U> where F: FnOnce(T) -> U, T: Clone {
+        match try_map_in_place(x, f) {
+            Ok(y) => y,
+            Err((shared, f)) => Rc::new(f((*shared).clone())),
+        }
+    }
+}
+
+/// `map_in_place` for `Arc<T>`.
+pub mod arc {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::{mem, ptr};
+    use super::{Dropper, Heap, raw_finish_map};
+
+    // Mirrors the private layout of `std::sync::Arc`'s backing
+    // `ArcInner<T>` (`strong` count, `weak` count, then the data),
+    // stable since `Arc` was introduced but not exposed by any public
+    // API. See `bip::rc::RcBoxLayout` for the same trick on `Rc`.
+    struct ArcInnerLayout<T> {
+        strong: AtomicUsize,
+        weak: AtomicUsize,
+        data: T,
+    }
+
+    /// Execute `f` on the value pointed at by `x`, producing an
+    /// `Arc<U>` that reuses `x`'s allocation, or hand `x` and `f` back
+    /// unchanged if `x` isn't uniquely owned.
+    ///
+    /// See `bip::rc::try_map_in_place`, whose unique-ownership
+    /// semantics this mirrors exactly (`Arc::get_mut` performs the
+    /// same strong/weak count check as `Rc::get_mut`).
+    pub fn try_map_in_place<T, U, F>(mut x: Arc<T>, f: F) -> Result<Arc<U>, (Arc<T>, F)>
+        where F: FnOnce(T) -> U
+    {
+        if Arc::get_mut(&mut x).is_none() {
+            return Err((x, f));
+        }
+
+        unsafe {
+            let heap = Heap;
+            let raw: *mut ArcInnerLayout<T> = mem::transmute(x);
+            let dropper = Dropper { ptr: raw, alloc: &heap };
+            let old = ptr::read(dropper.ptr);
+            let new = ArcInnerLayout { strong: old.strong, weak: old.weak, data: f(old.data) };
+            let new_raw = raw_finish_map(dropper.ptr, &heap, new);
+            mem::forget(dropper);
+            Ok(mem::transmute(new_raw))
+        }
+    }
+
+    /// Execute `f` on the value pointed at by `x`, producing an
+    /// `Arc<U>`.
+    ///
+    /// See `bip::rc::map_in_place`, whose unique-vs-shared semantics
+    /// this mirrors exactly.
+    pub fn map_in_place<T, U, F>(x: Arc<T>, f: F) -> Arc<U> where F: FnOnce(T) -> U, T: Clone {
+        match try_map_in_place(x, f) {
+            Ok(y) => y,
+            Err((shared, f)) => Arc::new(f((*shared).clone())),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::map_in_place;
+    use super::{map_in_place, map_in_place_in, map_in_place_slice, map_in_place_uninit,
+                try_map_in_place, AllocBox, Allocator, Heap};
     use std::thread::Thread;
     use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
 
@@ -123,13 +726,314 @@ mod tests {
     }
 
     #[test]
-    #[should_fail]
     fn mismatching_sizes() {
-        map_in_place(Box::new(1i32), |_| 0i16);
+        assert_eq!(map_in_place(Box::new(1i32), |x| x as i16), Box::new(1i16));
+        assert_eq!(map_in_place(Box::new(1i16), |x| x as i64), Box::new(1i64));
     }
+
     #[test]
-    #[should_fail]
     fn insufficient_alignment() {
-        map_in_place(Box::new([0u8; 8]), |_| 0u64);
+        assert_eq!(map_in_place(Box::new([0u8; 8]), |_| 0xdeadbeefu64),
+                   Box::new(0xdeadbeefu64));
+    }
+
+    #[test]
+    fn zero_sized() {
+        assert_eq!(map_in_place(Box::new(()), |_| 1i32), Box::new(1i32));
+        assert_eq!(map_in_place(Box::new(1i32), |_| ()), Box::new(()));
+    }
+
+    // forwards to `Heap`, but counts how many times memory is
+    // actually requested from the allocator, to prove `AllocBox` and
+    // `map_in_place_in` go through `A` rather than the global heap.
+    #[derive(Clone, Copy)]
+    struct CountingHeap<'a>(&'a AtomicUsize);
+
+    unsafe impl<'a> Allocator for CountingHeap<'a> {
+        unsafe fn allocate(&self, size: usize, align: usize) -> *mut u8 {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Heap.allocate(size, align)
+        }
+        unsafe fn deallocate(&self, ptr: *mut u8, size: usize, align: usize) {
+            Heap.deallocate(ptr, size, align)
+        }
+        unsafe fn reallocate(&self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> *mut u8 {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Heap.reallocate(ptr, old_size, size, align)
+        }
+    }
+
+    #[test]
+    fn alloc_box_in_place() {
+        static ALLOCS: AtomicUsize = ATOMIC_USIZE_INIT;
+        ALLOCS.store(0, Ordering::SeqCst);
+
+        let x = AllocBox::new_in(NotCopy(1), CountingHeap(&ALLOCS));
+        assert_eq!(ALLOCS.load(Ordering::SeqCst), 1);
+
+        let address_x = &*x as *const _;
+        let y = map_in_place_in(x, |x| NotCopy(x.0 + 1));
+        let address_y = &*y as *const _;
+
+        assert_eq!(*y, NotCopy(2));
+        assert_eq!(address_x, address_y);
+        // same-size, compatible-alignment map reuses the allocation.
+        assert_eq!(ALLOCS.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn slice_smoke() {
+        let x: Box<[i32]> = vec![1, 2, 3].into_boxed_slice();
+        let y = map_in_place_slice(x, |x| x as f32 + 1.0);
+        assert_eq!(&*y, &[2.0f32, 3.0, 4.0][..]);
+    }
+
+    #[test]
+    fn slice_in_place() {
+        let x: Box<[i32]> = vec![1, 2, 3].into_boxed_slice();
+        let address_x = x.as_ptr();
+        let y = map_in_place_slice(x, |x| x + 1);
+        assert_eq!(y.as_ptr() as *const i32, address_x);
+        assert_eq!(&*y, &[2, 3, 4][..]);
+    }
+
+    #[test]
+    fn slice_mismatching_sizes() {
+        let x: Box<[i32]> = vec![1, 2, 3].into_boxed_slice();
+        let y = map_in_place_slice(x, |x| x as i64);
+        assert_eq!(&*y, &[1i64, 2, 3][..]);
+    }
+
+    #[test]
+    fn slice_empty() {
+        let x: Box<[i32]> = Vec::new().into_boxed_slice();
+        let y = map_in_place_slice(x, |x| x as i64);
+        assert_eq!(&*y, &[][..]);
+    }
+
+    #[test]
+    fn slice_destructor_count() {
+        static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        struct Foo(u8);
+        impl Drop for Foo {
+            fn drop(&mut self) {
+                COUNT.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        struct Bar(u8);
+        impl Drop for Bar {
+            fn drop(&mut self) {
+                COUNT.fetch_add(10_000, Ordering::SeqCst);
+            }
+        }
+
+        COUNT.store(0, Ordering::SeqCst);
+        let value: Box<[Foo]> = vec![Foo(0), Foo(1), Foo(2), Foo(3)].into_boxed_slice();
+
+        let _ = Thread::scoped(move || {
+            let mut seen = 0;
+            map_in_place_slice(value, |x| -> Bar {
+                seen += 1;
+                if seen == 3 { panic!() }
+                Bar(x.0)
+            });
+        }).join();
+        // each of the 4 `Foo`s is dropped exactly once: the 2 that were
+        // successfully mapped drop when their closure call returns (`x`
+        // is bound by name and only its `Copy` field is read out, so it
+        // is still live at the end of the call), the panicking one
+        // drops while its closure call unwinds, and the unreached one
+        // is freed by the guard along with the 2 already-produced
+        // `Bar`s that never made it into a finished `Box<[Bar]>`.
+        assert_eq!(COUNT.load(Ordering::SeqCst), 2 * 10_000 + 4);
+    }
+
+    #[test]
+    fn try_map_ok() {
+        let x = Box::new(4i32);
+        let address_x = &*x as *const _;
+        let y = try_map_in_place(x, |x| Ok::<_, ()>(x as u32)).unwrap();
+        assert_eq!(*y, 4u32);
+        assert_eq!(&*y as *const _ as *const (), address_x as *const ());
+    }
+
+    #[test]
+    fn try_map_err() {
+        let x = Box::new(NotCopy(-1));
+        let address_x = &*x as *const _;
+
+        let (y, e) = try_map_in_place(x, |x| {
+            if x.0 >= 0 { Ok(x.0 as u32) } else { Err((x, "negative")) }
+        }).unwrap_err();
+
+        assert_eq!(e, "negative");
+        assert_eq!(*y, NotCopy(-1));
+        assert_eq!(&*y as *const _, address_x);
+    }
+
+    #[derive(Clone, PartialEq, Debug)]
+    struct CloneableNotCopy(i32);
+
+    #[test]
+    fn rc_unique() {
+        use std::rc::Rc;
+        use super::rc;
+
+        let x = Rc::new(CloneableNotCopy(1));
+        let address_x = &*x as *const _ as *const ();
+        let y = rc::map_in_place(x, |x| CloneableNotCopy(x.0 + 1));
+        assert_eq!(*y, CloneableNotCopy(2));
+        // unique ownership: the allocation is reused, not replaced.
+        assert_eq!(&*y as *const _ as *const (), address_x);
+    }
+
+    #[test]
+    fn rc_shared() {
+        use std::rc::Rc;
+        use super::rc;
+
+        let x = Rc::new(CloneableNotCopy(1));
+        let _other = x.clone();
+        let y = rc::map_in_place(x, |x| CloneableNotCopy(x.0 + 1));
+        assert_eq!(*y, CloneableNotCopy(2));
+        assert_eq!(*_other, CloneableNotCopy(1));
+    }
+
+    #[test]
+    fn rc_try_map_unique_reuses_allocation() {
+        use std::rc::Rc;
+        use super::rc;
+
+        let x = Rc::new(NotCopy(1));
+        let address_x = &*x as *const _ as *const ();
+        let y = rc::try_map_in_place(x, |x| x.0 as u32).unwrap();
+        assert_eq!(*y, 1u32);
+        assert_eq!(&*y as *const _ as *const (), address_x);
+    }
+
+    #[test]
+    fn rc_try_map_shared_gives_back_handle_and_closure() {
+        use std::rc::Rc;
+        use super::rc;
+
+        let x = Rc::new(NotCopy(1));
+        let other = x.clone();
+        let (x, f) = rc::try_map_in_place(x, |x| x.0 as u32).unwrap_err();
+        assert_eq!(*x, NotCopy(1));
+        assert_eq!(*other, NotCopy(1));
+        assert_eq!(f(NotCopy(5)), 5u32);
+    }
+
+    #[test]
+    fn arc_unique() {
+        use std::sync::Arc;
+        use super::arc;
+
+        let x = Arc::new(CloneableNotCopy(1));
+        let address_x = &*x as *const _ as *const ();
+        let y = arc::map_in_place(x, |x| CloneableNotCopy(x.0 + 1));
+        assert_eq!(*y, CloneableNotCopy(2));
+        // unique ownership: the allocation is reused, not replaced.
+        assert_eq!(&*y as *const _ as *const (), address_x);
+    }
+
+    #[test]
+    fn arc_shared() {
+        use std::sync::Arc;
+        use super::arc;
+
+        let x = Arc::new(CloneableNotCopy(1));
+        let other = x.clone();
+        let y = arc::map_in_place(x, |x| CloneableNotCopy(x.0 + 1));
+        assert_eq!(*y, CloneableNotCopy(2));
+        assert_eq!(*other, CloneableNotCopy(1));
+    }
+
+    #[test]
+    fn arc_try_map_unique_reuses_allocation() {
+        use std::sync::Arc;
+        use super::arc;
+
+        let x = Arc::new(NotCopy(1));
+        let address_x = &*x as *const _ as *const ();
+        let y = arc::try_map_in_place(x, |x| x.0 as u32).unwrap();
+        assert_eq!(*y, 1u32);
+        assert_eq!(&*y as *const _ as *const (), address_x);
+    }
+
+    #[test]
+    fn arc_try_map_shared_gives_back_handle_and_closure() {
+        use std::sync::Arc;
+        use super::arc;
+
+        let x = Arc::new(NotCopy(1));
+        let other = x.clone();
+        let (x, f) = arc::try_map_in_place(x, |x| x.0 as u32).unwrap_err();
+        assert_eq!(*x, NotCopy(1));
+        assert_eq!(*other, NotCopy(1));
+        assert_eq!(f(NotCopy(5)), 5u32);
+    }
+
+    #[test]
+    fn uninit_smoke() {
+        let x = Box::new(NotCopy(1));
+        let address_x = &*x as *const _ as *const ();
+        let y = unsafe {
+            map_in_place_uninit(x, |x, slot| {
+                slot.set(NotCopy2(x.0 as f32 + 1.0));
+            })
+        };
+        assert_eq!(*y, NotCopy2(2.0));
+        assert_eq!(&*y as *const _ as *const (), address_x);
+    }
+
+    #[test]
+    fn uninit_field_by_field() {
+        use std::ptr;
+
+        struct Pair { a: i32, b: i32 }
+
+        let x = Box::new(5i32);
+        let y = unsafe {
+            map_in_place_uninit(x, |x, slot| {
+                unsafe {
+                    let p = slot.as_mut_ptr();
+                    ptr::write(&mut (*p).a as *mut i32, x);
+                    ptr::write(&mut (*p).b as *mut i32, x * 2);
+                }
+            })
+        };
+        assert_eq!((y.a, y.b), (5, 10));
+    }
+
+    #[test]
+    fn uninit_destructor_count() {
+        static COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+        struct Foo { _x: u8 }
+        impl Drop for Foo {
+            fn drop(&mut self) {
+                COUNT.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+        struct Bar { _x: u8 }
+        impl Drop for Bar {
+            fn drop(&mut self) {
+                COUNT.fetch_add(10_000, Ordering::SeqCst);
+            }
+        }
+
+        COUNT.store(0, Ordering::SeqCst);
+        let value = Box::new(Foo { _x: 1 });
+
+        let _ = Thread::scoped(move || {
+            unsafe {
+                map_in_place_uninit(value, |_, _: &mut super::MaybeUninit<Bar>| {
+                    panic!()
+                });
+            }
+        }).join();
+        assert_eq!(COUNT.load(Ordering::SeqCst), 1);
     }
 }